@@ -1,54 +1,433 @@
+use rayon::prelude::*;
 use regex::Regex;
+use siphasher::sip128::{Hasher128, SipHasher13};
+use std::{
+    collections::HashMap,
+    fs,
+    hash::Hasher,
+    io::{Read, Seek, SeekFrom},
+};
 
-pub trait PmdAnalyzer {
-    type Result;
-    fn analyze(&self, source: &str) -> Self::Result;
+use crate::{
+    counter::{Lang, SourceCode},
+    runner::count_lines,
+};
+
+/// A single duplicated code block: the lines it spans in `source`, plus the
+/// other file it was found duplicated in (one `destination` per detected pair).
+#[derive(Debug, Clone)]
+pub struct Duplication {
+    pub lines: usize,
+    pub source: SourceCode,
+    pub destination: Vec<SourceCode>,
 }
 
-pub struct LinesAnalyzer;
+impl Duplication {
+    pub(crate) fn new(lines: usize) -> Self {
+        Self {
+            lines,
+            source: SourceCode::new(),
+            destination: Vec::new(),
+        }
+    }
+
+    pub(crate) fn add_destination(&mut self, des: SourceCode) {
+        self.destination.push(des);
+    }
+
+    pub(crate) fn add_lines(&mut self, line: usize) {
+        self.lines += line;
+    }
+
+    pub fn dup_rate(&self) -> f32 {
+        if self.destination.is_empty() {
+            return 0f32;
+        }
+
+        let rate = self.lines as f32 / self.destination[0].lines as f32;
+        if rate > 1.0 {
+            1.0
+        } else {
+            rate
+        }
+    }
 
-impl LinesAnalyzer {
-    pub fn new() -> Self {
-        Self
+    pub fn rate_of_source_code(&self) -> f32 {
+        let rate = self.lines as f32 / self.source.lines as f32;
+        if rate > 1.0 {
+            1.0
+        } else {
+            rate
+        }
     }
 }
 
-impl PmdAnalyzer for LinesAnalyzer {
-    type Result = Option<usize>;
+#[derive(Debug, Clone)]
+struct Token {
+    value: String,
+    file: usize,
+    line: usize,
+}
+
+/// Finds duplicated code across a set of source files without shelling out
+/// to an external tool: every file is tokenized into one global token
+/// stream, a suffix array over that stream turns "longest common substring
+/// shared by two files" into an adjacency check on sorted suffixes.
+pub struct CloneDetector {
+    minimum_tokens: usize,
+}
+
+impl CloneDetector {
+    pub fn new(minimum_tokens: usize) -> Self {
+        Self { minimum_tokens }
+    }
+
+    pub fn detect(&self, sources: &[SourceCode]) -> Vec<Duplication> {
+        let mut tokens: Vec<Token> = Vec::new();
+
+        for (file, source) in sources.iter().enumerate() {
+            let text = fs::read_to_string(&source.file).unwrap_or_default();
+            let lang = source.lang.unwrap_or(Lang::Swift);
+            tokens.extend(Self::tokenize(file, &text, lang));
+            // A sentinel keeps the longest-common-prefix of two suffixes from
+            // accidentally crossing a file boundary.
+            tokens.push(Token {
+                value: format!("\u{0}sentinel-{file}"),
+                file,
+                line: 0,
+            });
+        }
+
+        let suffix_array = Self::build_suffix_array(&tokens);
+        let lcp = Self::build_lcp_array(&tokens, &suffix_array);
 
-    fn analyze(&self, source: &str) -> Self::Result {
-        let reg = Regex::new(r"^Found a ([1-9]\d*) line").unwrap();
+        let mut clones = Vec::new();
+        let mut i = 1;
+        while i < suffix_array.len() {
+            if lcp[i] < self.minimum_tokens {
+                i += 1;
+                continue;
+            }
+
+            // A block duplicated 3+ times places several suffixes next to
+            // each other in sorted order, and the pair right at the run's
+            // edge can be same-file — so scan the whole run of
+            // above-threshold adjacent pairs for the first cross-file one,
+            // using that pair's own lcp value as the match length rather
+            // than the run's lowest value.
+            let mut j = i;
+            while tokens[suffix_array[j - 1]].file == tokens[suffix_array[j]].file
+                && j + 1 < suffix_array.len()
+                && lcp[j + 1] >= self.minimum_tokens
+            {
+                j += 1;
+            }
 
-        for cap in reg.captures_iter(source) {
-            if cap.len() > 1 {
-                return Some(cap[1].parse::<usize>().unwrap_or_default());
+            if tokens[suffix_array[j - 1]].file != tokens[suffix_array[j]].file {
+                let a = suffix_array[j - 1];
+                let b = suffix_array[j];
+                if let Some(dup) = Self::build_duplication(sources, &tokens, a, b, lcp[j]) {
+                    clones.push(dup);
+                }
             }
+
+            i = j + 1;
         }
-        None
+
+        clones
+    }
+
+    fn build_duplication(
+        sources: &[SourceCode],
+        tokens: &[Token],
+        a: usize,
+        b: usize,
+        len: usize,
+    ) -> Option<Duplication> {
+        let start = tokens.get(a)?;
+        let end = tokens.get(a + len - 1)?;
+        let dest_start = tokens.get(b)?;
+
+        let mut dup = Duplication::new(end.line.saturating_sub(start.line) + 1);
+        dup.source = sources.get(start.file)?.clone();
+        dup.add_destination(sources.get(dest_start.file)?.clone());
+        Some(dup)
+    }
+
+    fn build_suffix_array(tokens: &[Token]) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..tokens.len()).collect();
+        indices.sort_by(|&a, &b| {
+            tokens[a..]
+                .iter()
+                .map(|t| &t.value)
+                .cmp(tokens[b..].iter().map(|t| &t.value))
+        });
+        indices
     }
-}
 
-pub struct DuplicationAnalyzer;
+    fn build_lcp_array(tokens: &[Token], suffix_array: &[usize]) -> Vec<usize> {
+        let mut lcp = vec![0usize; suffix_array.len()];
+        for i in 1..suffix_array.len() {
+            let a = &tokens[suffix_array[i - 1]..];
+            let b = &tokens[suffix_array[i]..];
+            lcp[i] = a
+                .iter()
+                .zip(b.iter())
+                .take_while(|(x, y)| x.value == y.value)
+                .count();
+        }
+        lcp
+    }
+
+    fn tokenize(file: usize, source: &str, lang: Lang) -> Vec<Token> {
+        let comments = Regex::new(lang.comment_pattern()).unwrap();
+        let without_comments = comments.replace_all(source, "");
 
-impl DuplicationAnalyzer {
-    pub fn new() -> Self {
-        Self
+        let token_re = Regex::new(lang.token_pattern()).unwrap();
+
+        let mut tokens = Vec::new();
+        for (line, text) in without_comments.lines().enumerate() {
+            for mat in token_re.find_iter(text) {
+                tokens.push(Token {
+                    value: Self::canonicalize(mat.as_str()),
+                    file,
+                    line: line + 1,
+                });
+            }
+        }
+        tokens
+    }
+
+    /// Canonicalizes identifiers and literals so a renamed/retyped copy of a
+    /// block still matches token-for-token. Every word token becomes
+    /// `<ident>`, keywords included; only punctuation and operators are kept
+    /// as-is.
+    fn canonicalize(raw: &str) -> String {
+        match raw.chars().next() {
+            Some(c) if c.is_ascii_digit() => "<num>".to_owned(),
+            Some('"') | Some('\'') => "<str>".to_owned(),
+            Some(c) if c.is_alphabetic() || c == '_' => "<ident>".to_owned(),
+            _ => raw.to_owned(),
+        }
     }
 }
 
-impl PmdAnalyzer for DuplicationAnalyzer {
-    type Result = Option<(u32, String)>;
+/// A group of byte-identical files found by [`DuplicateFileFinder`].
+#[derive(Debug, Clone)]
+pub struct FileCluster {
+    pub files: Vec<SourceCode>,
+    pub wasted_lines: usize,
+}
 
-    fn analyze(&self, source: &str) -> Self::Result {
-        let reg = Regex::new(r"Starting at line ([1-9]\d*) of (/.+)?").unwrap();
+const PARTIAL_HASH_BLOCK: usize = 4096;
 
-        for cap in reg.captures_iter(source) {
-            if cap.len() > 2 {
-                let start = cap[1].parse::<u32>().unwrap_or_default();
-                let file = cap[2].to_owned();
-                return Some((start, file));
+/// Finds byte-identical files via a two-phase hash: files are first bucketed
+/// by size, then by a partial hash of their first block, and only the
+/// survivors of both cheap checks pay for a full-file hash. This keeps exact
+/// duplicate-file detection fast on large trees where most files are unique.
+pub struct DuplicateFileFinder {
+    ignore_trailing_whitespace: bool,
+}
+
+impl DuplicateFileFinder {
+    pub fn new(ignore_trailing_whitespace: bool) -> Self {
+        Self {
+            ignore_trailing_whitespace,
+        }
+    }
+
+    pub fn find(&self, sources: &[SourceCode]) -> Vec<FileCluster> {
+        // Raw byte length is only a valid pre-filter in exact mode: when
+        // trailing whitespace is ignored, two files that differ only by it
+        // have different lengths but must still reach the hash stages, so
+        // skip this phase entirely rather than bucket on a signal that
+        // would wrongly split them apart.
+        let all_sources: Vec<&SourceCode> = sources.iter().collect();
+        let candidates: Vec<&SourceCode> = if self.ignore_trailing_whitespace {
+            all_sources
+        } else {
+            let by_size = Self::bucket(&all_sources, |s| {
+                fs::metadata(&s.file).map(|m| m.len()).ok()
+            });
+            by_size
+                .into_values()
+                .filter(|group| group.len() > 1)
+                .flatten()
+                .collect()
+        };
+
+        let by_partial_hash = Self::bucket(&candidates, |s| {
+            self.hash_file(&s.file, Some(PARTIAL_HASH_BLOCK))
+        });
+        let candidates: Vec<&SourceCode> = by_partial_hash
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .flatten()
+            .collect();
+
+        let by_full_hash = Self::bucket(&candidates, |s| self.hash_file(&s.file, None));
+
+        by_full_hash
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .map(|files| {
+                let wasted_lines = files
+                    .first()
+                    .map(|f| count_lines(&f.file, true) * (files.len() - 1))
+                    .unwrap_or_default();
+                FileCluster {
+                    files: files.into_iter().cloned().collect(),
+                    wasted_lines,
+                }
+            })
+            .collect()
+    }
+
+    /// Hashing is the expensive part of a bucket pass, so it's computed for
+    /// every candidate across all cores; only the cheap fold into the
+    /// HashMap stays serial.
+    fn bucket<'a, K, F>(sources: &[&'a SourceCode], key_of: F) -> HashMap<K, Vec<&'a SourceCode>>
+    where
+        K: std::hash::Hash + Eq + Send,
+        F: Fn(&SourceCode) -> Option<K> + Sync,
+    {
+        let keyed: Vec<(K, &'a SourceCode)> = sources
+            .par_iter()
+            .filter_map(|&source| key_of(source).map(|key| (key, source)))
+            .collect();
+
+        let mut groups: HashMap<K, Vec<&'a SourceCode>> = HashMap::new();
+        for (key, source) in keyed {
+            groups.entry(key).or_default().push(source);
+        }
+        groups
+    }
+
+    /// Hashes the whole file, or just its first `limit` bytes when given.
+    /// Returns `None` if the file can't be opened.
+    fn hash_file(&self, path: &str, limit: Option<usize>) -> Option<u128> {
+        let mut file = fs::File::open(path).ok()?;
+        let mut buf = match limit {
+            Some(limit) => vec![0u8; limit],
+            None => Vec::new(),
+        };
+
+        let read = if let Some(limit) = limit {
+            file.read(&mut buf).ok()?
+        } else {
+            file.seek(SeekFrom::Start(0)).ok()?;
+            file.read_to_end(&mut buf).ok()?
+        };
+        buf.truncate(read);
+
+        if self.ignore_trailing_whitespace {
+            while matches!(buf.last(), Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n')) {
+                buf.pop();
             }
         }
-        None
+
+        let mut hasher = SipHasher13::new();
+        hasher.write(&buf);
+        let hash = hasher.finish128();
+        Some(((hash.h1 as u128) << 64) | hash.h2 as u128)
+    }
+}
+
+impl Default for DuplicateFileFinder {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn canonicalize_normalizes_idents_and_literals() {
+        assert_eq!(CloneDetector::canonicalize("foo"), "<ident>");
+        assert_eq!(CloneDetector::canonicalize("42"), "<num>");
+        assert_eq!(CloneDetector::canonicalize("\"hi\""), "<str>");
+        assert_eq!(CloneDetector::canonicalize("+"), "+");
+    }
+
+    /// Writes `content` to a fresh temp file and returns a [`SourceCode`]
+    /// pointing at it; each call gets a unique name so tests can run
+    /// concurrently without colliding on disk.
+    fn write_source(name: &str, content: &str, lang: Lang) -> SourceCode {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("cda-test-{}-{n}-{name}", std::process::id()));
+        fs::write(&path, content).unwrap();
+        SourceCode {
+            file: path.to_str().unwrap().to_owned(),
+            lines: content.lines().count(),
+            lang: Some(lang),
+        }
+    }
+
+    #[test]
+    fn detect_finds_a_cross_file_clone() {
+        let block = "fn shared_block() {\n    let x = 1;\n    let y = 2;\n    let z = x + y;\n    println!(\"{z}\");\n}\n";
+        let a = write_source("a.rs", block, Lang::Rust);
+        let b = write_source("b.rs", &format!("// unrelated header\n{block}"), Lang::Rust);
+
+        let detector = CloneDetector::new(5);
+        let dups = detector.detect(&[a.clone(), b.clone()]);
+
+        assert!(
+            !dups.is_empty(),
+            "expected at least one clone between {} and {}",
+            a.file,
+            b.file
+        );
+        fs::remove_file(&a.file).unwrap();
+        fs::remove_file(&b.file).unwrap();
+    }
+
+    #[test]
+    fn detect_ignores_files_with_no_shared_block() {
+        let a = write_source("c.rs", "fn one() { 1 }\n", Lang::Rust);
+        let b = write_source("d.rs", "let two = [2, 3];\n", Lang::Rust);
+
+        let detector = CloneDetector::new(5);
+        let dups = detector.detect(&[a.clone(), b.clone()]);
+
+        assert!(dups.is_empty());
+        fs::remove_file(&a.file).unwrap();
+        fs::remove_file(&b.file).unwrap();
+    }
+
+    #[test]
+    fn duplicate_file_finder_groups_identical_files() {
+        let content = "identical contents\n";
+        let a = write_source("e.rs", content, Lang::Rust);
+        let b = write_source("f.rs", content, Lang::Rust);
+        let c = write_source("g.rs", "different contents\n", Lang::Rust);
+
+        let finder = DuplicateFileFinder::new(false);
+        let clusters = finder.find(&[a.clone(), b.clone(), c.clone()]);
+
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].files.len(), 2);
+        fs::remove_file(&a.file).unwrap();
+        fs::remove_file(&b.file).unwrap();
+        fs::remove_file(&c.file).unwrap();
+    }
+
+    #[test]
+    fn duplicate_file_finder_can_ignore_trailing_whitespace() {
+        let a = write_source("h.rs", "same content", Lang::Rust);
+        let b = write_source("i.rs", "same content\n  ", Lang::Rust);
+
+        let exact = DuplicateFileFinder::new(false);
+        assert!(exact.find(&[a.clone(), b.clone()]).is_empty());
+
+        let lenient = DuplicateFileFinder::new(true);
+        assert_eq!(lenient.find(&[a.clone(), b.clone()]).len(), 1);
+
+        fs::remove_file(&a.file).unwrap();
+        fs::remove_file(&b.file).unwrap();
     }
 }
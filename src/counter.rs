@@ -1,7 +1,36 @@
-use std::{fmt::Display, fs, path::Path};
+use ignore::{
+    overrides::{Override, OverrideBuilder},
+    WalkBuilder,
+};
+use rayon::prelude::*;
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use crate::runner;
 
+/// Controls which files a [`Scanner`] skips while walking a directory tree.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Extra glob patterns to exclude, in addition to whatever's honored by
+    /// `use_default_ignores`.
+    pub excludes: Vec<String>,
+    /// Whether to honor `.gitignore`/`.ignore` files found along the walk.
+    pub use_default_ignores: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            excludes: Vec::new(),
+            use_default_ignores: true,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Lang {
     Swift,
@@ -12,13 +41,38 @@ pub enum Lang {
 }
 
 impl Lang {
-    pub fn extension(&self) -> &'static str {
+    pub const ALL: [Lang; 5] = [Lang::Swift, Lang::Java, Lang::Html, Lang::Kotlin, Lang::Rust];
+
+    /// File extensions recognized for this language, including common
+    /// aliases (e.g. `.kts` for Kotlin scripts, `.htm` for HTML).
+    pub fn extensions(&self) -> &'static [&'static str] {
         match *self {
-            Lang::Swift => "swift",
-            Lang::Java => "java",
-            Lang::Html => "html",
-            Lang::Kotlin => "kt",
-            Lang::Rust => "rs",
+            Lang::Swift => &["swift"],
+            Lang::Java => &["java"],
+            Lang::Html => &["html", "htm"],
+            Lang::Kotlin => &["kt", "kts"],
+            Lang::Rust => &["rs"],
+        }
+    }
+
+    /// The comment syntax to strip before tokenizing a file of this
+    /// language; everything but HTML uses C-style `//`/`/* */` comments.
+    pub fn comment_pattern(&self) -> &'static str {
+        match *self {
+            Lang::Html => r"(?s)<!--.*?-->",
+            Lang::Swift | Lang::Java | Lang::Kotlin | Lang::Rust => r"(?s)/\*.*?\*/|//[^\n]*",
+        }
+    }
+
+    /// The token grammar used to tokenize a file of this language for clone
+    /// detection. HTML has no C-like identifiers/operators, so it gets its
+    /// own tag-aware pattern instead of the shared one.
+    pub fn token_pattern(&self) -> &'static str {
+        match *self {
+            Lang::Html => r#"</?[A-Za-z][A-Za-z0-9-]*|[A-Za-z_][A-Za-z0-9_-]*|\d+(?:\.\d+)?|"(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)'|[^\sA-Za-z0-9_]"#,
+            Lang::Swift | Lang::Java | Lang::Kotlin | Lang::Rust => {
+                r#"[A-Za-z_][A-Za-z0-9_]*|\d+(?:\.\d+)?|"(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)'|[^\sA-Za-z0-9_]"#
+            }
         }
     }
 
@@ -31,6 +85,33 @@ impl Lang {
             Lang::Rust => "rust",
         }
     }
+
+    /// Parses a comma-separated list of language names (see [`FromStr`] for
+    /// the accepted spellings of a single language), or `all` for every
+    /// supported language.
+    pub fn parse_list(languages: &str) -> Result<Vec<Lang>, String> {
+        if languages.trim().eq_ignore_ascii_case("all") {
+            return Ok(Lang::ALL.to_vec());
+        }
+        languages.split(',').map(|lang| lang.parse()).collect()
+    }
+}
+
+impl std::str::FromStr for Lang {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "swift" => Ok(Lang::Swift),
+            "java" => Ok(Lang::Java),
+            "html" | "htm" => Ok(Lang::Html),
+            "kotlin" | "kt" | "kts" => Ok(Lang::Kotlin),
+            "rust" | "rs" => Ok(Lang::Rust),
+            other => Err(format!(
+                "unknown language '{other}', expected one of: swift, java, html, kotlin, rust"
+            )),
+        }
+    }
 }
 
 impl Display for Lang {
@@ -43,6 +124,7 @@ impl Display for Lang {
 pub struct SourceCode {
     pub file: String,
     pub lines: usize,
+    pub lang: Option<Lang>,
 }
 
 impl SourceCode {
@@ -50,6 +132,7 @@ impl SourceCode {
         SourceCode {
             file: String::new(),
             lines: 0,
+            lang: None,
         }
     }
 
@@ -58,16 +141,64 @@ impl SourceCode {
     }
 }
 
+/// Removes its directory (and everything in it) on drop, so a [`Scanner`]
+/// that extracted a `.zip`/`.jar` into a scratch directory cleans it up once
+/// the scan goes out of scope instead of leaking it into `temp_dir()`.
+#[derive(Debug)]
+struct ExtractedArchiveDir(PathBuf);
+
+impl Drop for ExtractedArchiveDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
 #[derive(Debug)]
 pub struct Scanner {
     sources: Vec<SourceCode>,
+    _extracted_dir: Option<ExtractedArchiveDir>,
 }
 
 impl Scanner {
     pub fn scan<P: AsRef<Path>>(root_dir: P, includes: &[Lang]) -> Self {
-        let sources = Self::read_dir(root_dir.as_ref(), includes);
+        Self::scan_with_options(root_dir, includes, &ScanOptions::default())
+    }
+
+    pub fn scan_with_options<P: AsRef<Path>>(
+        root_dir: P,
+        includes: &[Lang],
+        options: &ScanOptions,
+    ) -> Self {
+        let root_dir = root_dir.as_ref();
+
+        let (paths, extracted_dir) = if Self::is_archive(root_dir) {
+            match Self::extract_archive(root_dir, includes) {
+                Ok((paths, dir)) => (paths, Some(ExtractedArchiveDir(dir))),
+                Err(_) => (Vec::new(), None),
+            }
+        } else {
+            (Self::collect_paths(root_dir, includes, options), None)
+        };
 
-        Scanner { sources }
+        let mut sources: Vec<SourceCode> = paths
+            .par_iter()
+            .filter_map(|(path, lang)| {
+                let file_path = path.to_str()?;
+                let lines = runner::count_lines(path, true);
+                Some(SourceCode {
+                    file: file_path.to_owned(),
+                    lines,
+                    lang: Some(*lang),
+                })
+            })
+            .collect();
+
+        sources.sort_by(|a, b| a.file.cmp(&b.file));
+
+        Scanner {
+            sources,
+            _extracted_dir: extracted_dir,
+        }
     }
 
     pub fn source_codes(&self) -> &[SourceCode] {
@@ -106,51 +237,126 @@ impl Scanner {
         println!("{result}");
     }
 
-    fn read_dir(path: &Path, includes: &[Lang]) -> Vec<SourceCode> {
+    /// Walks `path`, returning every file whose extension matches one of
+    /// `includes`. Honors `.gitignore`/`.ignore` files along the way (unless
+    /// `options.use_default_ignores` is off) plus any `options.excludes`
+    /// globs, so ignored subtrees are pruned before line counting ever runs.
+    /// Kept serial (unlike the line counting and hashing done on the
+    /// result) since directory trees aren't easily divided into independent
+    /// parallel work.
+    fn collect_paths(
+        path: &Path,
+        includes: &[Lang],
+        options: &ScanOptions,
+    ) -> Vec<(PathBuf, Lang)> {
         if path.is_file() {
-            let ext = match path.extension() {
-                Some(ext) => ext,
-                None => return vec![],
+            return match Self::matching_lang(path, includes) {
+                Some(lang) => vec![(path.to_path_buf(), lang)],
+                None => vec![],
             };
+        }
 
-            let ext = match ext.to_str() {
-                Some(ext) => ext,
-                None => return vec![],
-            };
+        let overrides = Self::build_overrides(path, &options.excludes);
 
-            let lang_exts = includes
-                .iter()
-                .map(|l| l.extension())
-                .collect::<Vec<&'static str>>();
+        let mut paths: Vec<(PathBuf, Lang)> = vec![];
+        let walker = WalkBuilder::new(path)
+            .standard_filters(options.use_default_ignores)
+            .overrides(overrides)
+            .build();
 
-            if !lang_exts.contains(&ext) {
-                return vec![];
+        for entry in walker {
+            let Ok(entry) = entry else { continue };
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                continue;
             }
 
-            let lines = runner::count_lines(path, true);
+            let entry_path = entry.path();
+            if let Some(lang) = Self::matching_lang(entry_path, includes) {
+                paths.push((entry_path.to_path_buf(), lang));
+            }
+        }
+        paths
+    }
 
-            return match path.to_str() {
-                Some(file_path) => vec![SourceCode {
-                    file: file_path.to_owned(),
-                    lines,
-                }],
-                None => vec![],
-            };
+    fn matching_lang(path: &Path, includes: &[Lang]) -> Option<Lang> {
+        let ext = path.extension().and_then(|ext| ext.to_str())?;
+        includes
+            .iter()
+            .find(|lang| lang.extensions().contains(&ext))
+            .copied()
+    }
+
+    fn build_overrides(root: &Path, excludes: &[String]) -> Override {
+        let mut builder = OverrideBuilder::new(root);
+        for pattern in excludes {
+            let negated = format!("!{pattern}");
+            let _ = builder.add(&negated);
         }
+        // An empty/unbuildable override set matches everything, i.e. excludes nothing.
+        builder
+            .build()
+            .unwrap_or_else(|_| OverrideBuilder::new(root).build().unwrap())
+    }
 
-        if let Ok(red_dir) = fs::read_dir(path) {
-            let mut files: Vec<SourceCode> = vec![];
-            for entry in red_dir {
-                match entry {
-                    Ok(en) => files.append(&mut Self::read_dir(&en.path(), &includes)),
-                    Err(_) => files.append(&mut vec![]),
-                };
+    fn is_archive(path: &Path) -> bool {
+        matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("zip") | Some("jar")
+        )
+    }
+
+    /// Streams a `.zip`/`.jar` archive, extracting every matching-language
+    /// entry to a scratch directory under `std::env::temp_dir()` so the rest
+    /// of the pipeline can treat them as ordinary files on disk. Returns the
+    /// scratch directory alongside the extracted paths so the caller can
+    /// clean it up once the scan is done with it.
+    fn extract_archive(path: &Path, includes: &[Lang]) -> io::Result<(Vec<(PathBuf, Lang)>, PathBuf)> {
+        static ARCHIVE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let file = fs::File::open(path)?;
+        let mut archive =
+            zip::ZipArchive::new(file).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+        // pid + a per-process counter keeps this unique even when two
+        // archives being scanned in the same run share a file stem (e.g.
+        // `--root foo.jar --source other/foo.jar`).
+        let unique = ARCHIVE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!(
+            "cda-{}-{unique}-{}",
+            std::process::id(),
+            path.file_stem().and_then(|s| s.to_str()).unwrap_or("archive")
+        ));
+        fs::create_dir_all(&dir)?;
+
+        let mut paths = Vec::new();
+        for i in 0..archive.len() {
+            let mut entry = archive
+                .by_index(i)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+            if entry.is_dir() {
+                continue;
             }
 
-            return files;
-        } else {
-            return vec![];
+            let Some(entry_path) = entry.enclosed_name().map(|p| p.to_path_buf()) else {
+                continue;
+            };
+
+            let Some(lang) = Self::matching_lang(&entry_path, includes) else {
+                continue;
+            };
+
+            let dest = dir.join(&entry_path);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut out = fs::File::create(&dest)?;
+            io::copy(&mut entry, &mut out)?;
+            paths.push((dest, lang));
         }
+
+        Ok((paths, dir))
     }
 }
 
@@ -164,8 +370,16 @@ pub struct Counter {}
 
 #[cfg(test)]
 mod tests {
+    use super::Lang;
+
     #[test]
     fn lang() {
-        assert!(format!("{}", super::Lang::Swift) == "swift");
+        assert!(format!("{}", Lang::Swift) == "swift");
+    }
+
+    #[test]
+    fn parse_list_all_returns_every_language() {
+        assert_eq!(Lang::parse_list("all").unwrap(), Lang::ALL.to_vec());
+        assert_eq!(Lang::parse_list("ALL").unwrap(), Lang::ALL.to_vec());
     }
 }
@@ -1,18 +1,52 @@
 use clap::Parser;
-use regex::Regex;
+use serde::Serialize;
 use std::{
     collections::HashMap,
     fs::File,
-    io::{self, BufRead, BufReader, Write},
-    path::{Path, PathBuf},
-    process::Command,
+    io::{self, BufRead, BufReader},
+    path::Path,
 };
 
 use crate::{
-    analyzer::{DuplicationAnalyzer, LinesAnalyzer, PmdAnalyzer},
-    counter::{Lang, Scanner, SourceCode},
+    analyzer::{CloneDetector, Duplication, DuplicateFileFinder},
+    counter::{Lang, ScanOptions, Scanner},
 };
 
+/// Which kind of duplication report to produce.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Fuzzy, token-based clone detection within source files.
+    DuplicateCode,
+    /// Exact, byte-identical duplicate files.
+    DuplicateFiles,
+}
+
+/// Output format for the duplication report.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "kebab-case")]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicationReportEntry {
+    source: String,
+    destination: String,
+    duplicated_lines: usize,
+    dup_rate: f32,
+    rate_of_source_code: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicationReport {
+    total_rate: f32,
+    self_rate: f32,
+    results: Vec<DuplicationReportEntry>,
+}
+
 #[derive(Parser, Debug)]
 pub struct Args {
     /// Path to a source file, or directory containing source files to analyze. Zip and Jar files are also supported
@@ -27,219 +61,104 @@ pub struct Args {
     #[arg(short, long)]
     destination: String,
 
-    /// The source code language.
+    /// The source code language(s) to scan: a comma-separated list (e.g.
+    /// "swift,kotlin"), or "all" for every supported language.
     #[arg(short, long, default_value_t = String::from("swift"))]
     language: String,
 
     /// The minimum token length which should be reported as a duplicate.
     #[arg(long, default_value_t = 50)]
     minimum_tokens: usize,
-}
 
-impl Args {
-    fn is_destination_soruce_file(&self, file: &str) -> bool {
-        let dest_file_name = Path::new(&self.destination)
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap();
-
-        let reg = Regex::new(&format!("/{}/", dest_file_name)).unwrap();
-        reg.is_match(&file)
-    }
-}
+    /// Which kind of duplication report to produce.
+    #[arg(long, value_enum, default_value_t = Mode::DuplicateCode)]
+    mode: Mode,
 
-#[derive(Debug, Clone)]
-pub struct Duplication {
-    pub lines: usize,
-    pub source: SourceCode,
-    pub destination: Vec<SourceCode>,
-}
+    /// Exclude files/directories matching this glob pattern (repeatable).
+    #[arg(long = "exclude")]
+    excludes: Vec<String>,
 
-impl Duplication {
-    fn new(lines: usize) -> Self {
-        Self {
-            lines: lines,
-            source: SourceCode::new(),
-            destination: Vec::new(),
-        }
-    }
-    fn add_destination(&mut self, des: SourceCode) {
-        self.destination.push(des);
-    }
+    /// Don't honor .gitignore/.ignore files found while walking.
+    #[arg(long)]
+    no_default_ignores: bool,
 
-    fn clear_destination(&mut self) {
-        self.destination.clear();
-    }
+    /// Output format for the duplication report.
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
 
-    fn add_lines(&mut self, line: usize) {
-        self.lines += line;
-    }
-
-    fn dup_rate(&self) -> f32 {
-        if self.destination.is_empty() {
-            return 0f32;
-        }
+    /// In `--mode duplicate-files`, treat files as duplicates even if they
+    /// only differ by trailing whitespace, instead of requiring an exact
+    /// byte-for-byte match (the default).
+    #[arg(long)]
+    ignore_trailing_whitespace: bool,
+}
 
-        let rate = self.lines as f32 / self.destination[0].lines as f32;
-        if rate > 1.0 {
-            1.0
-        } else {
-            rate
+impl Args {
+    fn scan_options(&self) -> ScanOptions {
+        ScanOptions {
+            excludes: self.excludes.clone(),
+            use_default_ignores: !self.no_default_ignores,
         }
     }
 
-    fn rate_of_source_code(&self) -> f32 {
-        let rate = self.lines as f32 / self.source.lines as f32;
-        if rate > 1.0 {
-            1.0
-        } else {
-            rate
-        }
+    fn langs(&self) -> Result<Vec<Lang>, String> {
+        Lang::parse_list(&self.language)
     }
 }
 
 pub struct Runner;
 
 impl Runner {
-    pub fn run() -> Result<(), &'static str> {
+    pub fn run() -> Result<(), String> {
         let args = Args::parse();
-
-        let output = Command::new("which")
-            .arg("pmd")
-            .output()
-            .expect("failed to execute process");
-
-        if !output.status.success() {
-            Self::install_pmd()?;
+        match args.mode {
+            Mode::DuplicateCode => Self::detect_clones(&args),
+            Mode::DuplicateFiles => Self::detect_duplicate_files(&args),
         }
-        Self::exec_cpd(&args)
     }
 
-    fn install_pmd() -> Result<(), &'static str> {
-        println!("installing pmd...");
-
-        let err_msg = "Install 'pmd' failed, please install it manually. See: https://docs.pmd-code.org/latest/pmd_userdocs_installation.html";
-
-        let output = Command::new("brew")
-            .arg("install")
-            .arg("pmd")
-            .output()
-            .map_err(|_| err_msg)?;
+    fn detect_duplicate_files(args: &Args) -> Result<(), String> {
+        let langs = args.langs()?;
 
-        if !output.status.success() {
-            Err(err_msg)
-        } else {
-            Ok(())
-        }
-    }
+        let scanner = Scanner::scan_with_options(&args.root, &langs, &args.scan_options());
+        let finder = DuplicateFileFinder::new(args.ignore_trailing_whitespace);
+        let clusters = finder.find(scanner.source_codes());
 
-    fn exec_cpd(args: &Args) -> Result<(), &'static str> {
-        let root_dir = &args.root;
-        let minimum_tokens = format!("{}", args.minimum_tokens);
-        let output = Command::new("pmd")
-            .arg("cpd")
-            .arg("--minimum-tokens")
-            .arg(&minimum_tokens)
-            .arg("-d")
-            .arg(root_dir)
-            .arg("--language")
-            .arg(&args.language)
-            .output()
-            .expect("failed to execute process");
-
-        let code = output.status.code().unwrap_or_default();
-
-        if code == 0 {
-            println!("Everything is fine, no code duplications found.");
-            Ok(())
-        } else if code == 4 {
-            let mut path = PathBuf::from(root_dir);
-            path.push("report.txt");
-
-            let mut report_file = File::create(&path).unwrap();
-            report_file.write_all(&output.stdout).unwrap();
-            Self::analyze(&path)
-        } else {
-            Err("exited with an exception")
+        if clusters.is_empty() {
+            println!("Everything is fine, no duplicate files found.");
+            return Ok(());
         }
-    }
-
-    fn analyze<P>(pmd_report: P) -> Result<(), &'static str>
-    where
-        P: AsRef<Path>,
-    {
-        let args = Args::parse();
-
-        let lines_ana = LinesAnalyzer::new();
-        let file_ana = DuplicationAnalyzer::new();
-
-        let mut is_new_group = false;
-
-        let mut result = Vec::new();
-
-        let mut dup = Duplication::new(0);
-
-        let file = File::open(pmd_report).map_err(|_| "Cannot open pmd report")?;
-        let lines = BufReader::new(file).lines();
 
-        for line in lines {
-            if line.is_err() {
-                continue;
+        println!("Found {} duplicate file cluster(s):", clusters.len());
+        for cluster in &clusters {
+            println!();
+            for file in &cluster.files {
+                println!("  {}", file.file);
             }
+            println!("  wasted lines: {}", cluster.wasted_lines);
+        }
 
-            let string = &line.unwrap();
+        Ok(())
+    }
 
-            if string == "" && is_new_group {
-                if !dup.source.is_empty() {
-                    result.push(dup.clone());
-                }
-                dup = Duplication::new(0);
+    fn detect_clones(args: &Args) -> Result<(), String> {
+        let langs = args.langs()?;
 
-                is_new_group = false;
-                continue;
-            }
+        let scanner = Scanner::scan_with_options(&args.root, &langs, &args.scan_options());
+        let detector = CloneDetector::new(args.minimum_tokens);
+        let dups = detector.detect(scanner.source_codes());
 
-            if let Some(value) = lines_ana.analyze(string) {
-                is_new_group = true;
-                dup.lines = value;
-                continue;
-            }
-
-            let dup_file = match file_ana.analyze(string) {
-                Some(value) => value.1,
-                None => continue,
-            };
-
-            let is_source = &dup_file.contains(&args.source);
-            let is_dest = args.is_destination_soruce_file(&dup_file);
-            // println!("file: {}, is destination {}", &dup_file, is_dest);
-
-            let lines = count_lines(&dup_file, true);
-            let sc = SourceCode {
-                file: dup_file,
-                lines,
-            };
-
-            if *is_source && dup.source.is_empty() {
-                dup.source = sc;
-            } else if is_dest {
-                dup.add_destination(sc);
-            } else {
-                dup.clear_destination();
-            }
+        if dups.is_empty() {
+            println!("Everything is fine, no code duplications found.");
+        } else {
+            Self::pretty_printed(&dups, args)?;
         }
 
-        Self::pretty_printed(&result, &args);
-
         Ok(())
     }
 
-    fn pretty_printed(dups: &[Duplication], args: &Args) {
+    fn pretty_printed(dups: &[Duplication], args: &Args) -> Result<(), String> {
         let mut map: HashMap<&String, Duplication> = HashMap::new();
-
-        let mut max_width_of_source_file_name = 0;
-        let mut max_width_of_dest_file_name: usize = 0;
         for dup in dups {
             if dup.destination.is_empty() {
                 continue;
@@ -248,56 +167,115 @@ impl Runner {
             map.entry(&dup.source.file)
                 .and_modify(|d| d.add_lines(dup.lines))
                 .or_insert(dup.clone());
-
-            if dup.source.file.len() > max_width_of_source_file_name {
-                max_width_of_source_file_name = dup.source.file.len();
-            }
-
-            if dup.destination[0].file.len() > max_width_of_dest_file_name {
-                max_width_of_dest_file_name = dup.destination[0].file.len();
-            }
         }
 
-        println!("Found {} results:", map.len());
+        let langs = args.langs()?;
+
+        let options = args.scan_options();
+        let des_files =
+            Scanner::scan_with_options(&args.destination, &langs, &options).num_of_files();
+        let source_files =
+            Scanner::scan_with_options(&args.source, &langs, &options).num_of_files();
 
         let mut total_rate = 0.0;
         let mut self_rate = 0.0;
-        let mut result = String::new();
+        let mut results = Vec::with_capacity(map.len());
         for val in map.values() {
-            // println!("destination: {:#?}", val);
+            total_rate += val.dup_rate();
+            self_rate += val.rate_of_source_code();
 
-            result.push_str(&val.source.file);
-            for _ in 0..(max_width_of_source_file_name - &val.source.file.len()) {
+            results.push(DuplicationReportEntry {
+                source: val.source.file.clone(),
+                destination: val.destination[0].file.clone(),
+                duplicated_lines: val.lines,
+                dup_rate: val.dup_rate(),
+                rate_of_source_code: val.rate_of_source_code(),
+            });
+        }
+
+        let report = DuplicationReport {
+            total_rate: total_rate / des_files as f32,
+            self_rate: self_rate / source_files as f32,
+            results,
+        };
+
+        match args.format {
+            Format::Text => Self::print_text(&report),
+            Format::Json => Self::print_json(&report),
+            Format::Csv => Self::print_csv(&report),
+        }
+
+        Ok(())
+    }
+
+    fn print_text(report: &DuplicationReport) {
+        println!("Found {} results:", report.results.len());
+
+        let max_width_of_source_file_name =
+            report.results.iter().map(|e| e.source.len()).max().unwrap_or_default();
+        let max_width_of_dest_file_name = report
+            .results
+            .iter()
+            .map(|e| e.destination.len())
+            .max()
+            .unwrap_or_default();
+
+        let mut result = String::new();
+        for entry in &report.results {
+            result.push_str(&entry.source);
+            for _ in 0..(max_width_of_source_file_name - entry.source.len()) {
                 result.push(' ')
             }
             result.push_str(" ");
-            result.push_str(&val.destination[0].file);
-            for _ in 0..(max_width_of_dest_file_name - &val.destination[0].file.len()) {
+            result.push_str(&entry.destination);
+            for _ in 0..(max_width_of_dest_file_name - entry.destination.len()) {
                 result.push(' ')
             }
 
-            let code_lines = count_lines(&val.source.file, true);
+            let code_lines = count_lines(&entry.source, true);
             result.push_str(&format!("\t{}\t", code_lines));
-            result.push_str(&format!("\t{:.2}%\t", val.rate_of_source_code() * 100.0));
-            result.push_str(&format!("\t{}\t", val.lines));
-            result.push_str(&format!("\t{:.2}%\n", val.dup_rate() * 100.0));
-
-            total_rate += val.dup_rate();
-            self_rate += val.rate_of_source_code();
+            result.push_str(&format!("\t{:.2}%\t", entry.rate_of_source_code * 100.0));
+            result.push_str(&format!("\t{}\t", entry.duplicated_lines));
+            result.push_str(&format!("\t{:.2}%\n", entry.dup_rate * 100.0));
         }
         println!("{}", result);
 
-        // TODO: using language from args.
-        let langs = [Lang::Swift];
+        println!("Total rate: {:.2}%", report.total_rate * 100.0);
+        println!("Total rate of self: {:.2}%", report.self_rate * 100.0);
+    }
 
-        let des_files = Scanner::scan(&args.destination, &langs).num_of_files();
-        let source_files = Scanner::scan(&args.source, &langs).num_of_files();
+    fn print_json(report: &DuplicationReport) {
+        match serde_json::to_string_pretty(report) {
+            Ok(json) => println!("{json}"),
+            Err(_) => eprintln!("failed to serialize report as JSON"),
+        }
+    }
+
+    fn print_csv(report: &DuplicationReport) {
+        let mut writer = csv::Writer::from_writer(io::stdout());
+        for entry in &report.results {
+            if writer.serialize(entry).is_err() {
+                eprintln!("failed to write CSV row");
+                return;
+            }
+        }
+
+        // Carry the aggregate rates as a trailing TOTAL row in the same
+        // schema, so CSV consumers (e.g. a CI threshold check) don't have to
+        // switch formats just to read report.total_rate/self_rate.
+        let totals = DuplicationReportEntry {
+            source: "TOTAL".to_owned(),
+            destination: String::new(),
+            duplicated_lines: report.results.iter().map(|e| e.duplicated_lines).sum(),
+            dup_rate: report.total_rate,
+            rate_of_source_code: report.self_rate,
+        };
+        if writer.serialize(&totals).is_err() {
+            eprintln!("failed to write CSV total row");
+            return;
+        }
 
-        println!("Total rate: {:.2}%", total_rate / des_files as f32 * 100.0);
-        println!(
-            "Total rate of self: {:.2}%",
-            self_rate / source_files as f32 * 100.0
-        );
+        let _ = writer.flush();
     }
 }
 